@@ -0,0 +1,112 @@
+use std::collections::HashMap;
+use std::io::Write;
+
+use anyhow::Error;
+use py_spy::config::Config;
+use py_spy::StackTrace;
+use serde_json::json;
+
+/// A single duration event ("B"egin or "E"nd) in Chrome's JSON trace event
+/// format, as consumed by chrome://tracing and Perfetto.
+struct TraceEvent {
+    name: String,
+    ph: &'static str,
+    ts: f64,
+    pid: i64,
+    tid: i64,
+}
+
+/// Accumulates sampled stack traces into Chrome trace ("catapult") duration
+/// events, diffing each sample's frame stack against the previous one seen
+/// for that thread so that unchanged frames don't re-emit B/E pairs.
+pub struct Stats {
+    sampling_rate: f64,
+    // (pid, thread_id) -> (currently open frame names, outermost first; tick
+    // this thread was last sampled at)
+    open_stacks: HashMap<(i64, i64), (Vec<String>, u64)>,
+    events: Vec<TraceEvent>,
+}
+
+impl Stats {
+    pub fn new(config: &Config) -> Stats {
+        Stats {
+            sampling_rate: config.sampling_rate as f64,
+            open_stacks: HashMap::new(),
+            events: Vec::new(),
+        }
+    }
+
+    fn timestamp_us(&self, tick: u64) -> f64 {
+        (tick as f64) * 1_000_000.0 / self.sampling_rate
+    }
+
+    // `tick` is the sampler's tick count for the sample this trace came from.
+    // It has to come from the caller rather than a self-maintained counter:
+    // record() is called once per trace (i.e. per thread) within a single
+    // tick, not once per tick, so self-counting calls would make timestamps
+    // run faster than real time on any multi-threaded target.
+    pub fn record(&mut self, trace: &StackTrace, tick: u64) -> Result<(), Error> {
+        let pid = trace.pid as i64;
+        let tid = trace.thread_id as i64;
+        let ts = self.timestamp_us(tick);
+
+        // trace.frames is leaf-first; trace events are opened outermost-first.
+        let current: Vec<String> = trace.frames.iter().rev().map(|frame| frame.name.clone()).collect();
+
+        let (previous, last_tick) = self.open_stacks.entry((pid, tid)).or_insert_with(|| (Vec::new(), tick));
+        let common = previous
+            .iter()
+            .zip(current.iter())
+            .take_while(|(a, b)| a == b)
+            .count();
+
+        // close the frames that disappeared, innermost (last opened) first
+        for name in previous[common..].iter().rev() {
+            self.events.push(TraceEvent { name: name.clone(), ph: "E", ts, pid, tid });
+        }
+
+        // open the frames that newly appeared, outermost first
+        for name in current[common..].iter() {
+            self.events.push(TraceEvent { name: name.clone(), ph: "B", ts, pid, tid });
+        }
+
+        *previous = current;
+        *last_tick = tick;
+        Ok(())
+    }
+
+    pub fn write(&self, w: &mut dyn Write) -> Result<(), Error> {
+        let mut events = Vec::with_capacity(self.events.len());
+        for event in &self.events {
+            events.push(json!({
+                "name": event.name,
+                "ph": event.ph,
+                "ts": event.ts,
+                "pid": event.pid,
+                "tid": event.tid,
+            }));
+        }
+
+        // balance the trace: anything still open when sampling stopped gets
+        // closed at the tick that thread was last actually sampled at, not
+        // some shared global tick — a thread that stops being sampled early
+        // (e.g. it exits) shouldn't have its last frame reported as spanning
+        // the rest of the capture just because other threads kept running.
+        for (&(pid, tid), (stack, last_tick)) in self.open_stacks.iter() {
+            let ts = self.timestamp_us(*last_tick);
+            for name in stack.iter().rev() {
+                events.push(json!({
+                    "name": name,
+                    "ph": "E",
+                    "ts": ts,
+                    "pid": pid,
+                    "tid": tid,
+                }));
+            }
+        }
+
+        let trace = json!({ "traceEvents": events });
+        write!(w, "{}", serde_json::to_string(&trace)?)?;
+        Ok(())
+    }
+}
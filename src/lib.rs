@@ -1,7 +1,8 @@
 use py_spy::timer::Timer;
 use pyo3::prelude::*;
 use pyo3::exceptions::{PyRuntimeError, PyTypeError, PyValueError};
-use py_spy::config::{Config, FileFormat, RecordDuration};
+use pyo3::types::PyDict;
+use py_spy::config::{Config, FileFormat, LockingStrategy, RecordDuration};
 use py_spy::{Frame, StackTrace, sampler};
 use anyhow::{Error, format_err};
 use remoteprocess;
@@ -16,14 +17,18 @@ use std::io::{Read, Write};
 use std::sync::Arc;
 use std::sync::atomic::AtomicBool;
 mod speedscope;
+mod chrometrace;
 
 pub trait Recorder {
-    fn increment(&mut self, trace: &StackTrace) -> Result<(), Error>;
+    // `tick` is the sampler's tick count for the sample this trace came from,
+    // used by recorders (e.g. chrometrace) that need a real elapsed-time axis
+    // rather than one advancing once per recorded trace.
+    fn increment(&mut self, trace: &StackTrace, tick: u64) -> Result<(), Error>;
     fn write(&self, w: &mut dyn Write) -> Result<(), Error>;
 }
 
 impl Recorder for speedscope::Stats {
-    fn increment(&mut self, trace: &StackTrace) -> Result<(), Error> {
+    fn increment(&mut self, trace: &StackTrace, _tick: u64) -> Result<(), Error> {
         // println!("{:?}", trace);
         Ok(self.record(trace)?)
     }
@@ -32,17 +37,111 @@ impl Recorder for speedscope::Stats {
     }
 }
 
+impl Recorder for chrometrace::Stats {
+    fn increment(&mut self, trace: &StackTrace, tick: u64) -> Result<(), Error> {
+        self.record(trace, tick)
+    }
+    fn write(&self, w: &mut dyn Write) -> Result<(), Error> {
+        self.write(w)
+    }
+}
+
+
+/// The profile plus sampling-accuracy information collected while recording it.
+pub struct ProfileStats {
+    /// The serialized profile, or, when recording was started with an output
+    /// path, the path it was streamed to instead.
+    pub profile: String,
+    pub samples: u64,
+    pub errors: u64,
+    pub total_traces: u64,
+    pub timing_error_traces: u64,
+}
+
+/// A trace counts as a timing error once it arrives this many multiples of
+/// the configured sampling interval late.
+const LATE_SAMPLE_INTERVAL_MULTIPLE: f64 = 2.0;
+
+/// Whether samples are counted against wall-clock time or only while the
+/// thread is actually running on a CPU.
+///
+/// `CpuTime` is a filter, not a duration weighting: py-spy only reports
+/// `trace.active` as an instantaneous on-CPU/off-CPU flag per tick, not how
+/// long the thread has actually been running, so every active trace still
+/// counts as exactly one fixed-weight sample. Don't treat the resulting
+/// profile as proportional to wall-clock CPU time the way a true CPU
+/// profiler's output would be — it's wall-clock sampling restricted to
+/// on-CPU threads, not time-weighted by how long they were on-CPU.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum TimeMode {
+    WallTime,
+    CpuTime,
+}
 
 pub struct InternalProfiler {
-    profiler_thread: Option<thread::JoinHandle<Result<std::string::String, Error>>>,
+    profiler_thread: Option<thread::JoinHandle<Result<ProfileStats, Error>>>,
     running: Arc<AtomicBool>,
 }
 
+// Only formats record_samples actually implements a Recorder for; keep in
+// sync with the match in record_samples below.
+const SUPPORTED_FORMATS: [&str; 2] = ["speedscope", "chrometrace"];
+
 impl InternalProfiler {
-    pub fn new(pid: remoteprocess::Pid) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        pid: remoteprocess::Pid,
+        sampling_rate: u64,
+        format: &str,
+        blocking: bool,
+        subprocesses: bool,
+        gil_only: bool,
+        include_idle: bool,
+        include_thread_ids: bool,
+        time_mode: TimeMode,
+        already_locked: bool,
+        output_path: Option<String>,
+    ) -> Result<Self, Error> {
+        if !SUPPORTED_FORMATS.contains(&format) {
+            return Err(format_err!("Unsupported format '{}', expected one of {:?}", format, SUPPORTED_FORMATS));
+        }
+        if sampling_rate == 0 {
+            return Err(format_err!("sampling_rate must be greater than 0"));
+        }
 
-        let args = ["p".to_string(), "record".to_string(), "-p".to_string(), pid.to_string(), "-f".to_string(), "speedscope".to_string(), "--nonblocking".to_string(), "-r".to_string(), "1000".to_string()];
-        let config = Config::from_args(&args).unwrap();
+        let mut args = vec![
+            "p".to_string(), "record".to_string(),
+            "-p".to_string(), pid.to_string(),
+            "-f".to_string(), format.to_string(),
+            "-r".to_string(), sampling_rate.to_string(),
+        ];
+        if !blocking {
+            args.push("--nonblocking".to_string());
+        }
+        if subprocesses {
+            args.push("--subprocesses".to_string());
+        }
+        if gil_only {
+            args.push("--gil".to_string());
+        }
+        if include_idle {
+            args.push("--idle".to_string());
+        }
+        if include_thread_ids {
+            args.push("--threads".to_string());
+        }
+
+        let mut config = Config::from_args(&args)?;
+        if already_locked {
+            // we're a PyO3 extension loaded into the process being profiled, so
+            // --nonblocking only gets us part of the way there: it avoids
+            // self-deadlock but also gives up native-extension traces and OS
+            // thread-id resolution. AlreadyLocked tells the sampler the caller
+            // (us) already holds the relevant lock, so it can skip locking the
+            // process itself while still permitting native stack unwinding.
+            // Native unwinding itself stays off: --native is never passed above.
+            config.lock_process = LockingStrategy::AlreadyLocked;
+        }
         let sampling_rate = config.sampling_rate as f64;
 
         let ready = Arc::new(AtomicBool::new(false));
@@ -50,7 +149,7 @@ impl InternalProfiler {
         let running = Arc::new(AtomicBool::new(true));
         let running_clone = running.clone();
         let profiler_thread = thread::spawn(move || {
-            InternalProfiler::record_samples(pid, &config, running_clone, ready_clone)
+            InternalProfiler::record_samples(pid, &config, running_clone, ready_clone, time_mode, output_path)
         });
         for _sleep in Timer::new(sampling_rate as f64) {
             if ready.load(Ordering::SeqCst) {
@@ -58,15 +157,15 @@ impl InternalProfiler {
             }
         }
 
-        Self { profiler_thread: Some(profiler_thread), running }
+        Ok(Self { profiler_thread: Some(profiler_thread), running })
     }
 
-    fn record_samples(pid: remoteprocess::Pid, config: &Config, running: Arc<AtomicBool>, ready: Arc<AtomicBool>) -> Result<std::string::String, Error> {
+    fn record_samples(pid: remoteprocess::Pid, config: &Config, running: Arc<AtomicBool>, ready: Arc<AtomicBool>, time_mode: TimeMode, output_path: Option<String>) -> Result<ProfileStats, Error> {
         let mut output: Box<dyn Recorder> = match config.format {
             Some(FileFormat::flamegraph) => return Err(format_err!("Flamegraph not supported")),
             Some(FileFormat::speedscope) => Box::new(speedscope::Stats::new(config)),
             Some(FileFormat::raw) => return Err(format_err!("Raw not supported")),
-            Some(FileFormat::chrometrace) => return Err(format_err!("Chrometrace not supported")),
+            Some(FileFormat::chrometrace) => Box::new(chrometrace::Stats::new(config)),
             None => return Err(format_err!("A file format is required to record samples")),
         };
 
@@ -83,6 +182,12 @@ impl InternalProfiler {
 
         let mut errors = 0;
         let mut samples = 0;
+        let mut total_traces = 0;
+        let mut timing_error_traces = 0;
+
+        // tick count of the current sample, used by recorders to derive
+        // trace timestamps
+        let mut tick: u64 = 0;
 
         // let running = Arc::new(AtomicBool::new(false));
         // let r: Arc<AtomicBool> = running.clone();
@@ -91,10 +196,19 @@ impl InternalProfiler {
         // })?;
 
         let mut last_late_message = std::time::Instant::now();
+        // sampling_rate == 0 is rejected by InternalProfiler::new before this
+        // thread is spawned; dividing by it here would otherwise produce an
+        // infinite Duration and panic.
+        debug_assert!(config.sampling_rate > 0, "sampling_rate must be validated before record_samples runs");
+        let late_sample_threshold = Duration::from_secs_f64(LATE_SAMPLE_INTERVAL_MULTIPLE / config.sampling_rate as f64);
 
         ready.store(true, Ordering::SeqCst);
         for mut sample in sampler {
+            total_traces += 1;
             if let Some(delay) = sample.late {
+                if delay > late_sample_threshold {
+                    timing_error_traces += 1;
+                }
                 if delay > Duration::from_secs(1) {
                     if config.hide_progress {
                         // display a message if we're late, but don't spam the log
@@ -117,7 +231,13 @@ impl InternalProfiler {
             }
 
             for trace in sample.traces.iter_mut() {
-                if !(config.include_idle || trace.active) {
+                let on_cpu = match time_mode {
+                    // CpuTime mode always restricts to threads actually on-CPU,
+                    // regardless of --idle
+                    TimeMode::CpuTime => trace.active,
+                    TimeMode::WallTime => config.include_idle || trace.active,
+                };
+                if !on_cpu {
                     continue;
                 }
 
@@ -155,10 +275,15 @@ impl InternalProfiler {
                     }
                 }
 
+                // py-spy only reports active/owns_gil as an instantaneous flag,
+                // not a duration, so there's no sound way to weight a sample by
+                // observed on-CPU time; count every observed trace once.
                 samples += 1;
-                output.increment(trace)?;
+                output.increment(trace, tick)?;
             }
 
+            tick += 1;
+
             if let Some(sampling_errors) = sample.sampling_errors {
                 for (pid, e) in sampling_errors {
                     warn!("Failed to get stack trace from {}: {}", pid, e);
@@ -175,15 +300,31 @@ impl InternalProfiler {
             }
         }
 
-        
-        let mut write_buffer = Vec::new();
-        output.write(&mut write_buffer)?;
-        
+        let profile = match output_path {
+            Some(path) => {
+                let file = std::fs::File::create(&path)?;
+                let mut writer = std::io::BufWriter::new(file);
+                output.write(&mut writer)?;
+                writer.flush()?;
+                path
+            }
+            None => {
+                let mut write_buffer = Vec::new();
+                output.write(&mut write_buffer)?;
+                String::from_utf8(write_buffer)?
+            }
+        };
 
-        Ok(std::str::from_utf8(write_buffer.as_slice()).unwrap().to_string())
+        Ok(ProfileStats {
+            profile,
+            samples,
+            errors,
+            total_traces,
+            timing_error_traces,
+        })
     }
 
-    fn finish(&mut self) -> Result<String, Error> {
+    fn finish(&mut self) -> Result<ProfileStats, Error> {
         self.running.store(false, Ordering::SeqCst);
         let mut profiler_thread = Option::None;
         mem::swap(&mut self.profiler_thread, &mut profiler_thread);
@@ -192,12 +333,7 @@ impl InternalProfiler {
         }
         let thread_result = profiler_thread.unwrap().join();
         return match thread_result {
-            Ok(unpacked_result) => {
-                match unpacked_result {
-                    Ok(file_str) => Result::Ok(file_str.to_owned()),
-                    Err(error) => Result::Err(error),
-                }
-            },
+            Ok(unpacked_result) => unpacked_result,
             Err(_error) => Result::Err(format_err!("Failed to join profiling thread")),
         };
     }
@@ -212,15 +348,68 @@ pub struct PySpyProfiler {
 #[pymethods]
 impl PySpyProfiler {
     #[new]
-    fn new(pid: i32) -> Self {
-        Self {
-            profiler: InternalProfiler::new(pid),
-        }
+    #[pyo3(signature = (
+        pid,
+        sampling_rate = 1000,
+        format = "speedscope".to_string(),
+        blocking = false,
+        subprocesses = false,
+        gil_only = false,
+        include_idle = false,
+        include_thread_ids = false,
+        time_mode = "wall".to_string(),
+        already_locked = false,
+        output_path = None,
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        pid: i32,
+        sampling_rate: u64,
+        format: String,
+        blocking: bool,
+        subprocesses: bool,
+        gil_only: bool,
+        include_idle: bool,
+        include_thread_ids: bool,
+        time_mode: String,
+        already_locked: bool,
+        output_path: Option<String>,
+    ) -> PyResult<Self> {
+        let time_mode = match time_mode.as_str() {
+            "wall" => TimeMode::WallTime,
+            "cpu" => TimeMode::CpuTime,
+            other => return Err(PyValueError::new_err(format!("Unsupported time_mode '{other}', expected 'wall' or 'cpu'"))),
+        };
+        let profiler = InternalProfiler::new(
+            pid,
+            sampling_rate,
+            &format,
+            blocking,
+            subprocesses,
+            gil_only,
+            include_idle,
+            include_thread_ids,
+            time_mode,
+            already_locked,
+            output_path,
+        ).map_err(|error| PyValueError::new_err(error.to_string()))?;
+        Ok(Self { profiler })
     }
 
-    fn finish(&mut self) -> PyResult<String> {
+    fn finish(&mut self, py: Python<'_>) -> PyResult<PyObject> {
         match self.profiler.finish() {
-            Ok(file_str) => PyResult::Ok(file_str.to_owned()),
+            Ok(stats) => {
+                let late_pct = if stats.total_traces > 0 {
+                    (stats.timing_error_traces as f64 / stats.total_traces as f64) * 100.0
+                } else {
+                    0.0
+                };
+                let stats_dict = PyDict::new_bound(py);
+                stats_dict.set_item("total_samples", stats.samples)?;
+                stats_dict.set_item("sampling_errors", stats.errors)?;
+                stats_dict.set_item("late_sample_pct", late_pct)?;
+                Ok((stats.profile, stats_dict).into_py(py))
+            }
             Err(error) => PyResult::Err(PyRuntimeError::new_err(error.to_string())),
         }
     }
@@ -228,7 +417,7 @@ impl PySpyProfiler {
 
 impl Drop for PySpyProfiler {
     fn drop(&mut self) {
-        let _ = self.finish();
+        let _ = self.profiler.finish();
     }
 }
 